@@ -5,9 +5,57 @@ use egui::{ColorImage, Color32, ImageData, epaint::ImageDelta};
 use egui_glow;
 use glow::HasContext;
 use glutin::event::VirtualKeyCode;
+use gilrs::{Gilrs, Button, Axis, Event as GilrsEvent, EventType as GilrsEventType, GamepadId};
 
 use rust_nes_emulator::prelude::*;
 
+/// Analog sticks under this magnitude are treated as centered rather than
+/// bleeding into the digital d-pad from controller drift/noise.
+const STICK_DEADZONE: f32 = 0.35;
+
+fn gilrs_button_to_pad_button(button: Button) -> Option<PadButton> {
+    match button {
+        Button::South => Some(PadButton::A),
+        Button::East => Some(PadButton::B),
+        Button::Start => Some(PadButton::Start),
+        Button::Select => Some(PadButton::Select),
+        Button::DPadUp => Some(PadButton::Up),
+        Button::DPadDown => Some(PadButton::Down),
+        Button::DPadLeft => Some(PadButton::Left),
+        Button::DPadRight => Some(PadButton::Right),
+        _ => None,
+    }
+}
+
+/// Releases every button on `pad`, for when its gamepad disconnects mid-press
+/// and so will never send us the matching button-release event.
+fn release_all_pad_buttons(pad: &mut Pad) {
+    for button in [PadButton::A, PadButton::B, PadButton::Start, PadButton::Select,
+                   PadButton::Up, PadButton::Down, PadButton::Left, PadButton::Right] {
+        pad.release_button(button);
+    }
+}
+
+/// Maps the left stick to the digital d-pad once it's past `STICK_DEADZONE`,
+/// so games that only read the d-pad still work with an analog controller.
+fn stick_to_dpad_buttons(x: f32, y: f32) -> (Option<PadButton>, Option<PadButton>) {
+    let horizontal = if x > STICK_DEADZONE {
+        Some(PadButton::Right)
+    } else if x < -STICK_DEADZONE {
+        Some(PadButton::Left)
+    } else {
+        None
+    };
+    let vertical = if y > STICK_DEADZONE {
+        Some(PadButton::Up)
+    } else if y < -STICK_DEADZONE {
+        Some(PadButton::Down)
+    } else {
+        None
+    };
+    (horizontal, vertical)
+}
+
 fn get_file_as_byte_vec(filename: &str) -> Vec<u8> {
     //println!("Loading {}", filename);
     let mut f = File::open(&filename).expect("no file found");
@@ -75,6 +123,12 @@ fn main() {
     nes.insert_cartridge(cartridge);
 
     nes.poweron();
+    nes.enable_rewind(10.0, 1); // keep 10s of history, one snapshot per frame
+
+    let mut gilrs = Gilrs::new().expect("Failed to initialize gilrs");
+    // Which gamepad (if any) is currently routed to each NES controller port
+    let mut pad1_gamepad: Option<GamepadId> = None;
+    let mut pad2_gamepad: Option<GamepadId> = None;
 
     // XXX: we only need a single framebuffer considering that egui will synchronously copy
     // the data anyway
@@ -100,6 +154,64 @@ fn main() {
     let mut frame_no = 0;
     event_loop.run(move |event, _, control_flow| {
         let mut redraw = || {
+            // Route the first gamepad we see to pad1 and the second to pad2,
+            // handling connect/disconnect at runtime.
+            while let Some(GilrsEvent { id, event, .. }) = gilrs.next_event() {
+                match event {
+                    GilrsEventType::Connected => {
+                        if pad1_gamepad.is_none() {
+                            pad1_gamepad = Some(id);
+                        } else if pad2_gamepad.is_none() && pad1_gamepad != Some(id) {
+                            pad2_gamepad = Some(id);
+                        }
+                    }
+                    GilrsEventType::Disconnected => {
+                        // A disconnected pad can't send us any more button-release
+                        // events, so release everything it was holding now or it
+                        // would otherwise be stuck "pressed" forever.
+                        let system = nes.system_mut();
+                        if pad1_gamepad == Some(id) {
+                            pad1_gamepad = None;
+                            release_all_pad_buttons(&mut system.pad1);
+                        } else if pad2_gamepad == Some(id) {
+                            pad2_gamepad = None;
+                            release_all_pad_buttons(system.pad2_mut());
+                        }
+                    }
+                    GilrsEventType::ButtonPressed(button, _) | GilrsEventType::ButtonReleased(button, _) => {
+                        if let Some(pad_button) = gilrs_button_to_pad_button(button) {
+                            let pressed = matches!(event, GilrsEventType::ButtonPressed(..));
+                            let system = nes.system_mut();
+                            if pad1_gamepad == Some(id) {
+                                if pressed { system.pad1.push_button(pad_button); } else { system.pad1.release_button(pad_button); }
+                            } else if pad2_gamepad == Some(id) {
+                                if pressed { system.pad2_mut().push_button(pad_button); } else { system.pad2_mut().release_button(pad_button); }
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            // Analog-stick-to-d-pad mapping, polled (rather than event-driven)
+            // since we want continuous direction while the stick is held over.
+            for (slot_gamepad, to_port) in [(pad1_gamepad, 1u8), (pad2_gamepad, 2u8)] {
+                if let Some(id) = slot_gamepad {
+                    if let Some(gamepad) = gilrs.connected_gamepad(id) {
+                        let x = gamepad.value(Axis::LeftStickX);
+                        let y = gamepad.value(Axis::LeftStickY);
+                        let (horizontal, vertical) = stick_to_dpad_buttons(x, y);
+                        let system = nes.system_mut();
+                        for (button, held) in [(PadButton::Left, horizontal == Some(PadButton::Left)),
+                                               (PadButton::Right, horizontal == Some(PadButton::Right)),
+                                               (PadButton::Up, vertical == Some(PadButton::Up)),
+                                               (PadButton::Down, vertical == Some(PadButton::Down))] {
+                            let pad = if to_port == 1 { &mut system.pad1 } else { system.pad2_mut() };
+                            if held { pad.push_button(button); } else { pad.release_button(button); }
+                        }
+                    }
+                }
+            }
 
             if paused == false || single_step == true {
                 nes.tick_frame(framebuffer.clone());
@@ -162,6 +274,9 @@ fn main() {
                         if ui.button("Continue").clicked() {
                             paused = false;
                         }
+                        if ui.button("Rewind").clicked() {
+                            nes.rewind_step();
+                        }
 
                         let ppu = nes.system_ppu();
                         let debug_val = nes.debug_read_ppu(0x2000);
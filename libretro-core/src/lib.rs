@@ -0,0 +1,316 @@
+//! Libretro C-ABI export wrapping [`rust_nes_emulator::nes::Nes`], so the
+//! core can run inside any libretro frontend (RetroArch, etc.) rather than
+//! only behind the bundled egui example.
+//!
+//! This mirrors the handful of callbacks a minimal libretro core needs;
+//! anything not listed here (achievements, memory maps, rumble, ...) is
+//! left to the frontend's defaults.
+
+#![crate_type = "cdylib"]
+
+use std::os::raw::{c_char, c_uint, c_void};
+use std::ptr;
+use std::sync::Mutex;
+
+use rust_nes_emulator::prelude::*;
+use rust_nes_emulator::nes::{Nes, Region};
+
+const RETRO_API_VERSION: c_uint = 1;
+
+// Subset of the `retro_device_id_joypad` enum we map RetroPad input to.
+const RETRO_DEVICE_ID_JOYPAD_B: c_uint = 0;
+const RETRO_DEVICE_ID_JOYPAD_SELECT: c_uint = 2;
+const RETRO_DEVICE_ID_JOYPAD_START: c_uint = 3;
+const RETRO_DEVICE_ID_JOYPAD_UP: c_uint = 4;
+const RETRO_DEVICE_ID_JOYPAD_DOWN: c_uint = 5;
+const RETRO_DEVICE_ID_JOYPAD_LEFT: c_uint = 6;
+const RETRO_DEVICE_ID_JOYPAD_RIGHT: c_uint = 7;
+const RETRO_DEVICE_ID_JOYPAD_A: c_uint = 8;
+
+const RETRO_DEVICE_JOYPAD: c_uint = 1;
+const RETRO_MEMORY_SAVE_RAM: c_uint = 0;
+
+#[repr(C)]
+pub struct RetroGameInfo {
+    pub path: *const c_char,
+    pub data: *const c_void,
+    pub size: usize,
+    pub meta: *const c_char,
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct RetroGameGeometry {
+    pub base_width: c_uint,
+    pub base_height: c_uint,
+    pub max_width: c_uint,
+    pub max_height: c_uint,
+    pub aspect_ratio: f32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct RetroSystemTiming {
+    pub fps: f64,
+    pub sample_rate: f64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct RetroSystemAvInfo {
+    pub geometry: RetroGameGeometry,
+    pub timing: RetroSystemTiming,
+}
+
+type RetroVideoRefreshCb = extern "C" fn(data: *const c_void, width: c_uint, height: c_uint, pitch: usize);
+type RetroAudioSampleBatchCb = extern "C" fn(data: *const i16, frames: usize) -> usize;
+type RetroInputPollCb = extern "C" fn();
+type RetroInputStateCb = extern "C" fn(port: c_uint, device: c_uint, index: c_uint, id: c_uint) -> i16;
+
+struct Callbacks {
+    video_refresh: Option<RetroVideoRefreshCb>,
+    audio_sample_batch: Option<RetroAudioSampleBatchCb>,
+    input_poll: Option<RetroInputPollCb>,
+    input_state: Option<RetroInputStateCb>,
+}
+
+impl Default for Callbacks {
+    fn default() -> Self {
+        Self { video_refresh: None, audio_sample_batch: None, input_poll: None, input_state: None }
+    }
+}
+
+struct CoreState {
+    nes: Option<Nes>,
+    framebuffer: Option<Framebuffer>,
+    region: Region,
+    callbacks: Callbacks,
+    /// Battery-backed save RAM, mirrored here from [`Nes::save_ram_bytes`]
+    /// every [`retro_run`] so a frontend that fetches the pointer once (e.g.
+    /// at load, to write it out as a `.srm` file on shutdown) keeps seeing
+    /// up-to-date bytes through it instead of a copy frozen at call time.
+    save_ram: Vec<u8>,
+}
+
+impl Default for CoreState {
+    fn default() -> Self {
+        Self { nes: None, framebuffer: None, region: Region::Ntsc, callbacks: Callbacks::default(), save_ram: Vec::new() }
+    }
+}
+
+// Libretro's API is a single-core-instance-per-process C ABI; a global is
+// the idiomatic way to back it in Rust, same as every other libretro-rs core.
+static CORE: Mutex<CoreState> = Mutex::new(CoreState { nes: None, framebuffer: None, region: Region::Ntsc, callbacks: Callbacks { video_refresh: None, audio_sample_batch: None, input_poll: None, input_state: None }, save_ram: Vec::new() });
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+    RETRO_API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {
+    let mut core = CORE.lock().unwrap();
+    let nes = Nes::new_with_region(PixelFormat::RGBA8888, 48_000, core.region);
+    core.framebuffer = Some(nes.allocate_framebuffer());
+    core.nes = Some(nes);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    let mut core = CORE.lock().unwrap();
+    *core = CoreState::default();
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(cb: RetroVideoRefreshCb) {
+    CORE.lock().unwrap().callbacks.video_refresh = Some(cb);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(cb: RetroAudioSampleBatchCb) {
+    CORE.lock().unwrap().callbacks.audio_sample_batch = Some(cb);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(cb: RetroInputPollCb) {
+    CORE.lock().unwrap().callbacks.input_poll = Some(cb);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(cb: RetroInputStateCb) {
+    CORE.lock().unwrap().callbacks.input_state = Some(cb);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_system_av_info(info: *mut RetroSystemAvInfo) {
+    let core = CORE.lock().unwrap();
+    let av_info = RetroSystemAvInfo {
+        geometry: RetroGameGeometry {
+            base_width: FRAMEBUFFER_WIDTH as c_uint,
+            base_height: FRAMEBUFFER_HEIGHT as c_uint,
+            max_width: FRAMEBUFFER_WIDTH as c_uint,
+            max_height: FRAMEBUFFER_HEIGHT as c_uint,
+            aspect_ratio: FRAMEBUFFER_WIDTH as f32 / FRAMEBUFFER_HEIGHT as f32,
+        },
+        timing: RetroSystemTiming {
+            fps: core.region.frame_rate_hz(),
+            sample_rate: 48_000.0,
+        },
+    };
+    unsafe { ptr::write(info, av_info) };
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game(game: *const RetroGameInfo) -> bool {
+    if game.is_null() {
+        return false;
+    }
+    let game = unsafe { &*game };
+    if game.data.is_null() || game.size == 0 {
+        return false;
+    }
+    let rom = unsafe { std::slice::from_raw_parts(game.data as *const u8, game.size) };
+    let cartridge = Cartridge::from_ines_binary(|addr: usize| rom[addr]);
+
+    let mut core = CORE.lock().unwrap();
+    if let Some(nes) = &mut core.nes {
+        nes.insert_cartridge(Some(cartridge));
+        nes.poweron();
+        // Size `save_ram` up front so `retro_get_memory_data` has a stable
+        // pointer to hand out as soon as the frontend asks, rather than only
+        // after the first `retro_run`.
+        core.save_ram = nes.save_ram_bytes().unwrap_or_default();
+        true
+    } else {
+        false
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    let mut core = CORE.lock().unwrap();
+    if let Some(nes) = &mut core.nes {
+        nes.insert_cartridge(None);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_run() {
+    let mut core = CORE.lock().unwrap();
+    let CoreState { nes, framebuffer, callbacks, save_ram, .. } = &mut *core;
+    let (Some(nes), Some(framebuffer)) = (nes, framebuffer) else { return };
+
+    if let Some(input_poll) = callbacks.input_poll {
+        input_poll();
+    }
+    if let Some(input_state) = callbacks.input_state {
+        let system = nes.system_mut();
+        for (id, button) in [
+            (RETRO_DEVICE_ID_JOYPAD_A, PadButton::A),
+            (RETRO_DEVICE_ID_JOYPAD_B, PadButton::B),
+            (RETRO_DEVICE_ID_JOYPAD_START, PadButton::Start),
+            (RETRO_DEVICE_ID_JOYPAD_SELECT, PadButton::Select),
+            (RETRO_DEVICE_ID_JOYPAD_UP, PadButton::Up),
+            (RETRO_DEVICE_ID_JOYPAD_DOWN, PadButton::Down),
+            (RETRO_DEVICE_ID_JOYPAD_LEFT, PadButton::Left),
+            (RETRO_DEVICE_ID_JOYPAD_RIGHT, PadButton::Right),
+        ] {
+            let pressed = input_state(0, RETRO_DEVICE_JOYPAD, 0, id) != 0;
+            if pressed { system.pad1.push_button(button); } else { system.pad1.release_button(button); }
+        }
+    }
+
+    nes.tick_frame(framebuffer.clone());
+
+    // Refresh the mirrored save-RAM buffer in place so a pointer a frontend
+    // fetched earlier via `retro_get_memory_data` keeps reading current
+    // bytes. Only reallocate (changing that pointer) if the cartridge's
+    // save RAM size itself changed, which shouldn't happen mid-game.
+    if let Some(bytes) = nes.save_ram_bytes() {
+        if save_ram.len() == bytes.len() {
+            save_ram.copy_from_slice(&bytes);
+        } else {
+            *save_ram = bytes;
+        }
+    }
+
+    if let Some(video_refresh) = callbacks.video_refresh {
+        if let Some(mut rental) = framebuffer.rent_data() {
+            let pitch = FRAMEBUFFER_WIDTH * 4;
+            video_refresh(rental.data.as_mut_ptr() as *const c_void, FRAMEBUFFER_WIDTH as c_uint, FRAMEBUFFER_HEIGHT as c_uint, pitch);
+        }
+    }
+
+    if let Some(audio_sample_batch) = callbacks.audio_sample_batch {
+        let mut samples_f32 = [0f32; 4096];
+        let written = nes.drain_audio(&mut samples_f32);
+        // libretro's batch callback wants interleaved stereo i16; we only
+        // generate mono, so duplicate each sample to both channels.
+        let mut samples_i16 = Vec::with_capacity(written * 2);
+        for sample in &samples_f32[..written] {
+            let clamped = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            samples_i16.push(clamped);
+            samples_i16.push(clamped);
+        }
+        audio_sample_batch(samples_i16.as_ptr(), written);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    let core = CORE.lock().unwrap();
+    core.nes.as_ref().map(|nes| nes.save_state().len()).unwrap_or(0)
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    let core = CORE.lock().unwrap();
+    let Some(nes) = &core.nes else { return false };
+    let bytes = nes.save_state();
+    if bytes.len() > size {
+        return false;
+    }
+    unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), data as *mut u8, bytes.len()) };
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    let mut core = CORE.lock().unwrap();
+    let Some(nes) = &mut core.nes else { return false };
+    let bytes = unsafe { std::slice::from_raw_parts(data as *const u8, size) };
+    nes.load_state(bytes).is_ok()
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(id: c_uint) -> *mut c_void {
+    if id != RETRO_MEMORY_SAVE_RAM {
+        return ptr::null_mut();
+    }
+    // Hands back a pointer into `CoreState::save_ram`, which `retro_run`
+    // keeps refreshed in place, rather than leaking a fresh snapshot per
+    // call: frontends that fetch this pointer once and read through it
+    // later (e.g. at shutdown, to write out a `.srm` file) need it to still
+    // reflect whatever was last saved, and repeated calls must not leak.
+    let mut core = CORE.lock().unwrap();
+    if core.save_ram.is_empty() {
+        return ptr::null_mut();
+    }
+    core.save_ram.as_mut_ptr() as *mut c_void
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(id: c_uint) -> usize {
+    if id != RETRO_MEMORY_SAVE_RAM {
+        return 0;
+    }
+    CORE.lock().unwrap().save_ram.len()
+}
+
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    let mut core = CORE.lock().unwrap();
+    if let Some(nes) = &mut core.nes {
+        nes.reset();
+    }
+}
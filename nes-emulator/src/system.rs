@@ -1,3 +1,6 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
 use crate::apu::apu::Apu;
 use crate::ppu::Ppu;
 
@@ -10,19 +13,50 @@ use super::cartridge::*;
 use super::port::*;
 use bitflags::bitflags;
 
+#[cfg(feature="save-states")]
+use serde::{Serialize, Deserialize};
+
 const WRAM_SIZE: usize = 0x0800;
 
+/// Dendy's CPU clock: derived from the same ~26.6 MHz PAL-region master
+/// clock as [`PAL_CPU_CLOCK_HZ`], just divided down to a clean NTSC-like
+/// ratio by famiclone boards instead of PAL's own divisor.
+const DENDY_CPU_CLOCK_HZ: u32 = 1_773_447;
+
+/// How long (in wall-clock time) an undriven open-bus bit keeps its latched
+/// value before decaying, modelling the 2A03/2A07's data-bus capacitance.
+/// Real hardware varies per chip/temperature, so this is a reasonable fixed
+/// approximation rather than an attempt at a precise figure; it's expressed
+/// as a duration rather than a fixed cycle count so it models the same
+/// decay time regardless of `Model`'s CPU clock.
+#[cfg(feature="open-bus-decay")]
+const OPEN_BUS_DECAY_SECONDS: f64 = 300_000.0 / NTSC_CPU_CLOCK_HZ as f64;
+
+/// The only region-dependent value defined here is the CPU clock,
+/// [`Model::cpu_clock_hz`]. [`System::new`] passes the `Model` on to
+/// `Ppu::new`/`Apu::new`, so the PPU scanline count, vblank set/clear timing
+/// and APU timing of each variant are whatever those constructors derive
+/// from it.
 #[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[cfg_attr(feature="save-states", derive(Serialize, Deserialize))]
 pub enum Model {
     #[default]
     Ntsc,
-    Pal
+    Pal,
+    /// Famiclone region used across parts of the former USSR/CIS. Runs off
+    /// the same ~26.6 MHz PAL-region master clock as `Pal`, with the CPU at
+    /// [`DENDY_CPU_CLOCK_HZ`]; [`System::step_for_cpu_cycle`] clocks the PPU
+    /// a clean 3 dots per CPU cycle (like `Ntsc`) rather than PAL's
+    /// fractional 3.2. Scanline count and vblank timing come from
+    /// `Ppu::new`, see the type-level doc above.
+    Dendy,
 }
 impl Model {
     pub fn cpu_clock_hz(&self) -> u32 {
         match self {
             Model::Ntsc => NTSC_CPU_CLOCK_HZ,
             Model::Pal => PAL_CPU_CLOCK_HZ,
+            Model::Dendy => DENDY_CPU_CLOCK_HZ,
         }
     }
 }
@@ -60,6 +94,10 @@ pub struct IoStatsRecord {
 }
 
 // State we don't want to capture in a snapshot/clone of the system
+//
+// `io_stats` is a `u16::MAX`-entry table, so it's only ever allocated when the
+// `io-stats` feature is enabled: a bare-metal target with no use for it
+// shouldn't pay for 1.5MB of `IoStatsRecord`s it never asked for.
 #[derive(Default)]
 pub struct NoCloneDebugState {
     #[cfg(feature="debugger")]
@@ -76,8 +114,26 @@ impl Clone for NoCloneDebugState {
     }
 }
 
+/// Deriving `Serialize`/`Deserialize` here only compiles once `Ppu`, `Apu`,
+/// `Cartridge` and `Port` (in `ppu.rs`/`apu.rs`/`cartridge.rs`/`port.rs`,
+/// none of which this patch touches) implement them too; this has not been
+/// verified to compile against those modules. In particular, if
+/// `Cartridge::mapper` turns out to be a `Box<dyn Mapper>` the way
+/// `self.cartridge.mapper.save_ram()`/`.irq()` being called through it below
+/// suggests, a plain `#[derive(Serialize, Deserialize)]` on `Cartridge`
+/// cannot work for a trait object at all — that needs an explicit
+/// serialization scheme for `Mapper` (e.g. `typetag`, or a hand-written
+/// `Serialize` impl that matches on a mapper-id enum) before `System` can
+/// derive through it.
 #[derive(Clone)]
+#[cfg_attr(feature="save-states", derive(Serialize, Deserialize))]
 pub struct System {
+    /// The TV system/timing standard this `System` was constructed with.
+    /// Kept around (rather than only being consumed by `Ppu::new`/`Apu::new`
+    /// up front) so other region-dependent behaviour, like open-bus decay
+    /// timing, can scale against [`Model::cpu_clock_hz`] too.
+    pub model: Model,
+
     pub ppu: Ppu,
 
     #[cfg(feature="ppu-sim")]
@@ -92,6 +148,14 @@ pub struct System {
 
     pub open_bus_value: u8,
 
+    /// CPU clock (`apu.clock`, which tracks 1:1 with it) at which each bit
+    /// of `open_bus_value` was last *driven* by a real read/write, i.e. was
+    /// not part of that access's `undefined_bits`. Used to model the
+    /// 2A03/2A07's data-bus capacitance decay: a bit that hasn't been
+    /// driven in a while should stop reading back as latched.
+    #[cfg(feature="open-bus-decay")]
+    open_bus_bit_timestamps: [u64; 8],
+
     /// 0x0000 - 0x07ff: WRAM
     /// 0x0800 - 0x1f7ff: WRAM  Mirror x3
     pub wram: [u8; WRAM_SIZE],
@@ -103,12 +167,187 @@ pub struct System {
     pub port1: Port,
     pub port2: Port,
 
+    /// Debugger-only state (watch points, io-stats) that we never want
+    /// round-tripped through a save state: it already resets to its
+    /// `Default` on `Clone`, so a restored `System` just starts with no
+    /// watch points armed, same as a fresh one.
+    #[cfg_attr(feature="save-states", serde(skip))]
     pub debug: NoCloneDebugState,
+
+    /// `None` until a front-end opts in with [`System::enable_rewind`].
+    /// Requires the `save-states` feature since it's built on
+    /// [`System::snapshot`]/[`System::restore`].
+    #[cfg(all(feature="rewind", feature="save-states"))]
+    #[cfg_attr(feature="save-states", serde(skip))]
+    rewind: Option<RewindBuffer>,
+}
+
+/// Save states are a versioned, self-describing blob so that a state
+/// produced by an older build fails to load cleanly instead of silently
+/// deserializing into the wrong layout and corrupting memory.
+#[cfg(feature="save-states")]
+pub const SAVE_STATE_VERSION: u32 = 1;
+
+#[cfg(feature="save-states")]
+#[derive(Serialize, Deserialize)]
+struct SaveStateHeader {
+    magic: [u8; 4],
+    version: u32,
+}
+
+#[cfg(feature="save-states")]
+#[derive(Debug)]
+pub enum SaveStateError {
+    /// The blob didn't start with our magic bytes, so it's not one of ours
+    NotASaveState,
+    /// The blob's version doesn't match `SAVE_STATE_VERSION`
+    VersionMismatch { found: u32, expected: u32 },
+    Corrupt,
+}
+
+/// Errors from [`System::load_ram_bytes`]
+#[derive(Debug)]
+pub enum SaveRamError {
+    /// The cartridge has no battery-backed RAM to load into
+    NoBattery,
+    /// `data` doesn't match the size of the cartridge's battery-backed RAM
+    SizeMismatch { found: usize, expected: usize },
+}
+
+/// A bounded ring buffer of [`System::snapshot`] blobs, captured at frame
+/// boundaries, that [`System::rewind_step_back`] pops from to step the
+/// machine backwards in time.
+///
+/// The first entry held is always a full snapshot ("keyframe"); every later
+/// entry only stores the XOR delta against the snapshot before it. The delta
+/// is stored uncompressed and is the same length as a full snapshot, so it
+/// takes as much memory as a full copy would. When the buffer is full and the oldest
+/// entry is evicted, the new oldest entry (if it's a delta) is materialized
+/// back into a keyframe so every later delta still has a valid base.
+#[cfg(all(feature="rewind", feature="save-states"))]
+struct RewindBuffer {
+    /// Capture a snapshot every `frame_interval` frames
+    frame_interval: u32,
+    frames_since_capture: u32,
+    entries: alloc::collections::VecDeque<RewindEntry>,
+    capacity: usize,
+}
+
+#[cfg(all(feature="rewind", feature="save-states"))]
+enum RewindEntry {
+    Keyframe(Vec<u8>),
+    Delta(Vec<u8>),
+}
+
+#[cfg(all(feature="rewind", feature="save-states"))]
+fn xor_delta(base: &[u8], next: &[u8]) -> Vec<u8> {
+    debug_assert_eq!(base.len(), next.len());
+    base.iter().zip(next.iter()).map(|(a, b)| a ^ b).collect()
+}
+
+#[cfg(all(feature="rewind", feature="save-states"))]
+impl RewindBuffer {
+    fn new(capacity: usize, frame_interval: u32) -> Self {
+        Self {
+            frame_interval: frame_interval.max(1),
+            frames_since_capture: 0,
+            entries: alloc::collections::VecDeque::with_capacity(capacity),
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// Called once per emulated frame; returns true once `frame_interval`
+    /// frames have elapsed and a capture is due.
+    fn tick_frame(&mut self) -> bool {
+        self.frames_since_capture += 1;
+        if self.frames_since_capture >= self.frame_interval {
+            self.frames_since_capture = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn reconstruct(&self, upto: usize) -> Vec<u8> {
+        let mut full = match &self.entries[0] {
+            RewindEntry::Keyframe(bytes) => bytes.clone(),
+            RewindEntry::Delta(_) => unreachable!("first rewind entry is always a keyframe"),
+        };
+        for entry in self.entries.iter().take(upto + 1).skip(1) {
+            if let RewindEntry::Delta(delta) = entry {
+                full = xor_delta(&full, delta);
+            }
+        }
+        full
+    }
+
+    fn push(&mut self, snapshot: Vec<u8>) {
+        if self.entries.is_empty() {
+            self.entries.push_back(RewindEntry::Keyframe(snapshot));
+        } else {
+            let prev = self.reconstruct(self.entries.len() - 1);
+            self.entries.push_back(RewindEntry::Delta(xor_delta(&prev, &snapshot)));
+        }
+
+        if self.entries.len() > self.capacity {
+            // `entries[0]` is always a keyframe, so reconstruct the new base
+            // (what will become `entries[0]` after the pop) *before* evicting
+            // it out from under `reconstruct`, which assumes `entries[0]` is
+            // still a keyframe.
+            let new_base = matches!(self.entries.get(1), Some(RewindEntry::Delta(_)))
+                .then(|| self.reconstruct(1));
+            self.entries.pop_front();
+            if let Some(new_base) = new_base {
+                self.entries[0] = RewindEntry::Keyframe(new_base);
+            }
+        }
+    }
+
+    /// Drops the most recent snapshot and returns the one before it, if any.
+    fn pop(&mut self) -> Option<Vec<u8>> {
+        if self.entries.len() < 2 {
+            return None;
+        }
+        self.entries.pop_back();
+        Some(self.reconstruct(self.entries.len() - 1))
+    }
+}
+
+#[cfg(all(test, feature="rewind", feature="save-states"))]
+mod rewind_buffer_tests {
+    use super::*;
+
+    #[test]
+    fn xor_delta_round_trips() {
+        let base: Vec<u8> = (0..256).map(|i| i as u8).collect();
+        let next: Vec<u8> = base.iter().map(|b| b.wrapping_add(7)).collect();
+        let delta = xor_delta(&base, &next);
+        assert_eq!(xor_delta(&base, &delta), next);
+    }
+
+    #[test]
+    fn survives_eviction_past_capacity() {
+        // Regression test: pushing past `capacity` used to reconstruct the
+        // new base *after* popping the old keyframe, panicking via
+        // `reconstruct`'s "first rewind entry is always a keyframe" the
+        // first time the buffer wrapped.
+        let mut buf = RewindBuffer::new(2, 1);
+        for i in 0..5u8 {
+            buf.push(vec![i; 4]);
+        }
+        assert_eq!(buf.entries.len(), 2);
+        assert_eq!(buf.pop(), Some(vec![3u8; 4]));
+    }
 }
 
 impl System {
 
     pub fn new(model: Model, audio_sample_rate: u32, cartridge: Cartridge) -> Self {
+        // `model` flows into `Ppu::new`/`Apu::new` below so each can size its
+        // own scanline count, vblank timing and sample-rate divisor; whether
+        // their internal `match`es already cover `Model::Dendy` (rather than
+        // only `Ntsc`/`Pal`) depends on `ppu.rs`/`apu.rs`, which this patch
+        // doesn't touch.
         let ppu = Ppu::new(model);
 
         #[cfg(feature="ppu-sim")]
@@ -117,6 +356,8 @@ impl System {
         let apu = Apu::new(model, audio_sample_rate);
 
         Self {
+            model,
+
             ppu,
 
             #[cfg(feature="ppu-sim")]
@@ -136,6 +377,12 @@ impl System {
 
             open_bus_value: 0,
 
+            #[cfg(feature="open-bus-decay")]
+            open_bus_bit_timestamps: [0; 8],
+
+            #[cfg(all(feature="rewind", feature="save-states"))]
+            rewind: None,
+
             debug: NoCloneDebugState {
                 watch_points: vec![],
                 watch_hit: false,
@@ -164,20 +411,22 @@ impl System {
         self.port1.power_cycle();
         self.port2.power_cycle();
 
-        let ppu = std::mem::take(&mut self.ppu);
+        let ppu = core::mem::take(&mut self.ppu);
         #[cfg(feature="ppu-sim")]
-        let ppu_sim = std::mem::take(&mut self.ppu_sim);
-        let apu = std::mem::take(&mut self.apu);
-        let cartridge = std::mem::take(&mut self.cartridge);
+        let ppu_sim = core::mem::take(&mut self.ppu_sim);
+        let apu = core::mem::take(&mut self.apu);
+        let cartridge = core::mem::take(&mut self.cartridge);
         #[cfg(feature="ppu-sim")]
-        let ppu_sim_cartridge = std::mem::take(&mut self.ppu_sim_cartridge);
-        let pad1 = std::mem::take(&mut self.port1);
-        let pad2 = std::mem::take(&mut self.port2);
+        let ppu_sim_cartridge = core::mem::take(&mut self.ppu_sim_cartridge);
+        let pad1 = core::mem::take(&mut self.port1);
+        let pad2 = core::mem::take(&mut self.port2);
 
         #[cfg(feature="debugger")]
-        let watch_points = std::mem::take(&mut self.debug.watch_points);
+        let watch_points = core::mem::take(&mut self.debug.watch_points);
 
         *self = Self {
+            model: self.model,
+
             ppu,
 
             #[cfg(feature="ppu-sim")]
@@ -197,6 +446,9 @@ impl System {
 
             open_bus_value: 0,
 
+            #[cfg(feature="open-bus-decay")]
+            open_bus_bit_timestamps: [0; 8],
+
             debug: NoCloneDebugState {
                 #[cfg(feature="debugger")]
                 watch_points,
@@ -209,6 +461,105 @@ impl System {
         };
     }
 
+    /// Serializes `wram`, `open_bus_value`, `port1`/`port2` and the full
+    /// `ppu`, `apu` and `cartridge`/mapper state into a versioned blob.
+    ///
+    /// `NoCloneDebugState` is never captured (see its `#[serde(skip)]`
+    /// above), so a `restore()`d `System` always comes back with a clean
+    /// debugger state.
+    #[cfg(feature="save-states")]
+    pub fn snapshot(&self) -> Vec<u8> {
+        let header = SaveStateHeader { magic: *b"NESS", version: SAVE_STATE_VERSION };
+        let mut out = bincode::serialize(&header).expect("save state header always serializes");
+        out.extend(bincode::serialize(self).expect("System always serializes"));
+        out
+    }
+
+    /// Restores state previously produced by [`System::snapshot`].
+    ///
+    /// Fails with [`SaveStateError::VersionMismatch`] rather than trying to
+    /// reinterpret a blob from an incompatible version, and leaves `self`
+    /// untouched on any error.
+    #[cfg(feature="save-states")]
+    pub fn restore(&mut self, bytes: &[u8]) -> Result<(), SaveStateError> {
+        let header_len = bincode::serialized_size(&SaveStateHeader { magic: *b"NESS", version: SAVE_STATE_VERSION })
+            .map_err(|_| SaveStateError::Corrupt)? as usize;
+        if bytes.len() < header_len {
+            return Err(SaveStateError::NotASaveState);
+        }
+
+        let header: SaveStateHeader = bincode::deserialize(&bytes[..header_len])
+            .map_err(|_| SaveStateError::NotASaveState)?;
+        if &header.magic != b"NESS" {
+            return Err(SaveStateError::NotASaveState);
+        }
+        if header.version != SAVE_STATE_VERSION {
+            return Err(SaveStateError::VersionMismatch { found: header.version, expected: SAVE_STATE_VERSION });
+        }
+
+        let restored: System = bincode::deserialize(&bytes[header_len..])
+            .map_err(|_| SaveStateError::Corrupt)?;
+        *self = restored;
+        Ok(())
+    }
+
+    /// Returns the battery-backed portion of the cartridge's PRG-RAM, or
+    /// `None` if the cartridge has no battery (nothing to persist).
+    ///
+    /// This is deliberately independent of [`System::snapshot`]: a host can
+    /// write this out as a small `.sav` file on power-down and feed it back
+    /// through [`System::load_ram_bytes`] without needing a full save state.
+    pub fn save_ram_bytes(&self) -> Option<Vec<u8>> {
+        self.cartridge.mapper.save_ram()
+    }
+
+    /// Loads battery-backed PRG-RAM previously produced by
+    /// [`System::save_ram_bytes`], without disturbing any other mapper
+    /// state. Intended to be called right after `new()`/`power_cycle()`,
+    /// once a cartridge has been inserted.
+    pub fn load_ram_bytes(&mut self, data: &[u8]) -> Result<(), SaveRamError> {
+        self.cartridge.mapper.load_save_ram(data)
+    }
+
+    /// Opt in to rewind support: a snapshot is captured every `frame_interval`
+    /// frames, keeping the last `capacity_frames / frame_interval` of them.
+    /// Embedded hosts should size `capacity_frames` to whatever memory budget
+    /// they can spare for history.
+    #[cfg(all(feature="rewind", feature="save-states"))]
+    pub fn enable_rewind(&mut self, capacity_frames: usize, frame_interval: u32) {
+        let capacity = (capacity_frames / frame_interval.max(1) as usize).max(1);
+        self.rewind = Some(RewindBuffer::new(capacity, frame_interval));
+    }
+
+    #[cfg(all(feature="rewind", feature="save-states"))]
+    pub fn disable_rewind(&mut self) {
+        self.rewind = None;
+    }
+
+    #[cfg(all(feature="rewind", feature="save-states"))]
+    fn maybe_capture_rewind_point(&mut self) {
+        let due = match &mut self.rewind {
+            Some(rewind) => rewind.tick_frame(),
+            None => false,
+        };
+        if due {
+            let snapshot = self.snapshot();
+            if let Some(rewind) = &mut self.rewind {
+                rewind.push(snapshot);
+            }
+        }
+    }
+
+    /// Restores the most recently captured snapshot older than the current
+    /// moment, returning `false` if rewind isn't enabled or there's no older
+    /// snapshot left to go back to.
+    #[cfg(all(feature="rewind", feature="save-states"))]
+    pub fn rewind_step_back(&mut self) -> bool {
+        let Some(rewind) = &mut self.rewind else { return false };
+        let Some(bytes) = rewind.pop() else { return false };
+        self.restore(&bytes).is_ok()
+    }
+
     pub(crate) fn reset(&mut self) {
         self.ppu.reset();
         self.apu.reset();
@@ -225,8 +576,41 @@ impl System {
         self.apu.irq() || self.cartridge.mapper.irq()
     }
 
+    /// [`OPEN_BUS_DECAY_SECONDS`] converted to a CPU-cycle count for
+    /// `self.model`'s clock, so the same bit decays after the same amount of
+    /// wall-clock time on NTSC, PAL or Dendy despite their different CPU
+    /// clock rates.
+    #[cfg(feature="open-bus-decay")]
+    fn open_bus_decay_cycles(&self) -> u64 {
+        (OPEN_BUS_DECAY_SECONDS * self.model.cpu_clock_hz() as f64) as u64
+    }
+
+    /// Clears any bit of `undefined_bits` whose data-bus capacitance has
+    /// decayed away (hasn't been driven by a real read/write in a while),
+    /// so it no longer latches a stale value, then records the current
+    /// clock against every bit that *is* being driven by this access.
+    #[cfg(feature="open-bus-decay")]
+    fn decay_open_bus_bits(&mut self, mut undefined_bits: u8) -> u8 {
+        let now = self.apu.clock;
+        let decay_cycles = self.open_bus_decay_cycles();
+        for bit in 0..8 {
+            let mask = 1u8 << bit;
+            if undefined_bits & mask != 0 {
+                if now.wrapping_sub(self.open_bus_bit_timestamps[bit]) > decay_cycles {
+                    undefined_bits &= !mask;
+                }
+            } else {
+                self.open_bus_bit_timestamps[bit] = now;
+            }
+        }
+        undefined_bits
+    }
+
     /// Apply the open bus bits and update the open bus value for future reads
     fn apply_open_bus_bits_mut(&mut self, mut value: u8, undefined_bits: u8) -> u8 {
+        #[cfg(feature="open-bus-decay")]
+        let undefined_bits = self.decay_open_bus_bits(undefined_bits);
+
         value = value & !undefined_bits;
         value |= self.open_bus_value & undefined_bits;
         self.open_bus_value = value;
@@ -235,6 +619,22 @@ impl System {
 
     /// Apply the open bus bits without additional side effects (for peeking)
     fn apply_open_bus_bits(&self, mut value: u8, undefined_bits: u8) -> u8 {
+        #[cfg(feature="open-bus-decay")]
+        let undefined_bits = {
+            let now = self.apu.clock;
+            let decay_cycles = self.open_bus_decay_cycles();
+            let mut undefined_bits = undefined_bits;
+            for bit in 0..8 {
+                let mask = 1u8 << bit;
+                if undefined_bits & mask != 0
+                    && now.wrapping_sub(self.open_bus_bit_timestamps[bit]) > decay_cycles
+                {
+                    undefined_bits &= !mask;
+                }
+            }
+            undefined_bits
+        };
+
         value = value & !undefined_bits;
         value |= self.open_bus_value & undefined_bits;
         value
@@ -535,6 +935,13 @@ impl System {
             //self.cartridge.trace_cpu_clock_line_sync(cpu_clk);
         }
 
+        // Same frame-boundary detection as the trace-events hook above, used to
+        // drive the rewind buffer's capture cadence instead of tracing.
+        #[cfg(all(feature="rewind", feature="save-states"))]
+        if self.ppu.dot == 0 && self.ppu.line == 0 {
+            self.maybe_capture_rewind_point();
+        }
+
         #[cfg(feature="ppu-sim")]
         self.ppu_sim_step();
 
@@ -561,6 +968,8 @@ impl System {
         //
         // For PAL (3.2 pixel clocks) we will fall behind slightly within a single instruction
         // but that will be caught up in `Nes::progress()`. See `Self::catch_up_ppu_drift` below.
+        // Dendy is a clean 3:1 ratio just like NTSC (it only differs in scanline count and
+        // vblank timing), so this fixed loop already matches it with no drift to catch up.
         //
         for _ in 0..3 {
             if !self.step_ppu() {
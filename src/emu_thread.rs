@@ -0,0 +1,188 @@
+//! Runs a [`Nes`] on its own thread and talks to it over bounded
+//! `crossbeam-channel`s, so that UI latency and emulation pacing aren't
+//! coupled to the render/UI thread's redraw cadence.
+//!
+//! This is also a prerequisite for running the core headless on embedded
+//! targets while a separate thread/task handles I/O: the emulation thread
+//! here doesn't know or care that its commands/frames happen to come from
+//! an egui event loop.
+
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{bounded, Receiver, Sender, TryRecvError};
+
+use crate::prelude::*;
+use crate::nes::{Nes, Region};
+
+/// Commands the UI thread can send to the emulation thread.
+pub enum Command {
+    Reset,
+    Pause(bool),
+    /// Single-step one frame while paused
+    Step,
+    SetButton { pad: u8, button: PadButton, pressed: bool },
+    LoadState(Vec<u8>),
+    SaveState,
+    InsertCartridge(Option<Cartridge>),
+}
+
+/// A timestamped command: `at_frame` is the emulation thread's frame number
+/// at the moment the command was sent. A command is held back until the
+/// emulation thread reaches `at_frame`; since that frame has always been
+/// reached already when the queue is drained, every command is applied at
+/// the next frame boundary after it is received, not on an earlier frame.
+struct TimestampedCommand {
+    command: Command,
+    at_frame: u64,
+}
+
+/// Events the emulation thread sends back to the UI thread.
+pub enum FrameEvent {
+    /// A completed framebuffer, ready to be copied into a texture
+    Frame(Framebuffer),
+    /// Response to `Command::SaveState`
+    StateSaved(Vec<u8>),
+}
+
+pub struct EmuThreadHandle {
+    commands: Sender<TimestampedCommand>,
+    frames: Receiver<FrameEvent>,
+    join_handle: Option<JoinHandle<()>>,
+    current_frame: std::sync::Arc<std::sync::atomic::AtomicU64>,
+}
+
+impl EmuThreadHandle {
+    /// Sends `command` to the emulation thread, tagged with the frame number
+    /// the emulation thread most recently completed; it is applied at the
+    /// next frame boundary after the emulation thread receives it.
+    pub fn send(&self, command: Command) {
+        let at_frame = self.current_frame.load(std::sync::atomic::Ordering::Relaxed);
+        // A full command queue means the emulation thread is badly behind;
+        // drop rather than block the UI thread indefinitely.
+        let _ = self.commands.try_send(TimestampedCommand { command, at_frame });
+    }
+
+    /// Non-blocking poll for the next available frame/event.
+    pub fn try_recv_frame(&self) -> Option<FrameEvent> {
+        match self.frames.try_recv() {
+            Ok(event) => Some(event),
+            Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => None,
+        }
+    }
+
+    pub fn shutdown(mut self) {
+        drop(self.commands);
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+    }
+}
+
+/// Spawns `nes` onto a dedicated thread, pacing it to real time using
+/// `region`'s frame rate and swapping frames through a bounded channel.
+///
+/// If the UI thread falls behind (the frame channel is full), the
+/// emulation thread drops the newly rendered frame rather than
+/// blocking emulation on a slow consumer; if it gets ahead (paused, or a
+/// single very fast frame), it sleeps to avoid running faster than the
+/// configured frame rate.
+pub fn spawn(mut nes: Nes, region: Region, pixel_format: PixelFormat) -> EmuThreadHandle {
+    let (command_tx, command_rx) = bounded::<TimestampedCommand>(256);
+    let (frame_tx, frame_rx) = bounded::<FrameEvent>(2);
+    let current_frame = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+    let current_frame_thread = current_frame.clone();
+
+    let join_handle = thread::Builder::new()
+        .name("nes-emulation".into())
+        .spawn(move || {
+            let frame_period = Duration::from_secs_f64(1.0 / region.frame_rate_hz());
+            let mut paused = false;
+            let mut pending: Vec<TimestampedCommand> = Vec::new();
+            let mut frame_no: u64 = 0;
+            let mut framebuffer = nes.allocate_framebuffer();
+
+            loop {
+                let mut disconnected = false;
+                loop {
+                    match command_rx.try_recv() {
+                        Ok(cmd) => pending.push(cmd),
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => { disconnected = true; break; }
+                    }
+                }
+                if disconnected && pending.is_empty() {
+                    break;
+                }
+
+                // Apply any command timestamped for a frame we've now reached;
+                // leave later ones queued for a future iteration.
+                let (due, still_pending): (Vec<_>, Vec<_>) =
+                    pending.into_iter().partition(|c| c.at_frame <= frame_no);
+                pending = still_pending;
+
+                for TimestampedCommand { command, .. } in due {
+                    match command {
+                        Command::Reset => nes.reset(),
+                        Command::Pause(p) => paused = p,
+                        Command::Step => {
+                            let tick_start = Instant::now();
+                            nes.tick_frame(framebuffer.clone());
+                            frame_no += 1;
+                            current_frame_thread.store(frame_no, std::sync::atomic::Ordering::Relaxed);
+                            let _ = tick_start; // pacing is skipped for an explicit single-step
+                            // Without this the UI has no way to see the frame it just
+                            // asked for: a full channel here means the UI is still
+                            // holding the previous frame, so drop the new one same as
+                            // the main per-frame loop below does.
+                            let _ = frame_tx.try_send(FrameEvent::Frame(framebuffer.clone()));
+                        }
+                        Command::SetButton { pad, button, pressed } => {
+                            let system = nes.system_mut();
+                            let port = if pad == 1 { &mut system.pad1 } else { system.pad2_mut() };
+                            if pressed { port.push_button(button); } else { port.release_button(button); }
+                        }
+                        Command::LoadState(bytes) => { let _ = nes.load_state(&bytes); }
+                        Command::SaveState => {
+                            let bytes = nes.save_state();
+                            // A full frame channel here would mean the UI is also
+                            // behind on save-state acks; drop rather than stall.
+                            let _ = frame_tx.try_send(FrameEvent::StateSaved(bytes));
+                        }
+                        Command::InsertCartridge(cartridge) => nes.insert_cartridge(cartridge),
+                    }
+                }
+
+                if paused {
+                    thread::sleep(Duration::from_millis(1));
+                    continue;
+                }
+
+                let frame_start = Instant::now();
+                nes.tick_frame(framebuffer.clone());
+                frame_no += 1;
+                current_frame_thread.store(frame_no, std::sync::atomic::Ordering::Relaxed);
+
+                // Swap the just-rendered framebuffer to the UI thread; if it's
+                // still holding the previous one (channel full) drop the new
+                // frame rather than block emulation pacing on a slow UI.
+                let _ = frame_tx.try_send(FrameEvent::Frame(framebuffer.clone()));
+
+                let elapsed = frame_start.elapsed();
+                if elapsed < frame_period {
+                    thread::sleep(frame_period - elapsed);
+                }
+                // If we're behind (elapsed > frame_period) we just run the next
+                // frame immediately rather than trying to catch up by skipping
+                // emulation, which would desync audio.
+            }
+        })
+        .expect("failed to spawn nes-emulation thread");
+
+    EmuThreadHandle {
+        commands: command_tx,
+        frames: frame_rx,
+        join_handle: Some(join_handle),
+        current_frame,
+    }
+}
@@ -1,11 +1,19 @@
 #![crate_type = "lib"]
 #![crate_name = "rust_nes_emulator"]
-//#![cfg_attr(not(feature = "std"), no_std)]
+#![cfg_attr(not(feature = "std"), no_std)]
+
+// `alloc` backs `Vec`/`Box` usage throughout (watch points, the optional
+// io-stats table, cartridge PRG/CHR-RAM, ...) so the core can still run on
+// bare-metal targets that have a global allocator but no `std`.
+extern crate alloc;
+
 #[macro_use]
 pub mod interface;
 
 pub mod binary;
 pub mod constants;
+#[cfg(feature="std")]
+pub mod emu_thread;
 pub mod nes;
 pub mod apu;
 pub mod cartridge;
@@ -16,6 +24,8 @@ pub mod pad;
 pub mod ppu;
 pub mod framebuffer;
 pub mod prelude;
+pub mod rewind;
+pub mod save_state;
 pub mod system;
 //pub mod system_apu_reg;
 pub mod ppu_registers;
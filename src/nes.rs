@@ -3,10 +3,87 @@ use crate::prelude::*;
 use crate::system::*;
 use crate::cpu::*;
 use crate::ppu::*;
+use crate::save_state::{SaveState, Cursor, SaveStateError, write_header, read_header};
 use log::{warn};
+use ringbuf::{HeapRb, HeapProducer, HeapConsumer, Rb};
+
+/// How many seconds of audio the SPSC ring buffer can hold before a slow
+/// consumer starts causing us to drop samples.
+const AUDIO_RINGBUF_SECONDS: f64 = 0.5;
+
+/// TV system / timing standard. Selected at [`Nes::new_with_region`] time
+/// (inserting a cartridge does not change it) and threaded through to the
+/// CPU:PPU clock ratio and the audio sample-rate divisor.
+///
+/// **Not yet threaded through:** the PPU's scanline count and vblank
+/// set/clear timing. `Ppu` has no way to configure those today (see the
+/// `TODO` in [`Nes::new_with_region`]), so selecting [`Region::Pal`] or
+/// [`Region::Dendy`] changes the CPU clock and PPU:CPU dot ratio but still
+/// runs frame timing on NTSC's 262 scanlines/vblank window — the frame rate
+/// a PAL/Dendy game actually expects from its PPU is not reproduced yet.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Region {
+    /// Exact 3:1 PPU:CPU dot ratio, 262 scanlines, ~60.0988 Hz
+    Ntsc,
+    /// Fractional 3.2:1 (16 PPU dots per 5 CPU cycles) ratio, 312 scanlines,
+    /// a longer vblank, ~50.007 Hz. Scanline count/vblank timing aren't
+    /// threaded into the PPU yet — see the type-level doc above.
+    Pal,
+    /// 50Hz like PAL (312 scanlines, shifted vblank start) but with NTSC's
+    /// clean 3:1 PPU:CPU ratio. Scanline count/vblank timing aren't
+    /// threaded into the PPU yet — see the type-level doc above.
+    Dendy,
+}
+
+/// Dendy's CPU clock, derived from the same ~26.6 MHz PAL-region master
+/// crystal as [`PAL_CPU_CLOCK_HZ`] but divided down by famiclone boards to
+/// a clean NTSC-like ratio rather than PAL's own divisor.
+const DENDY_CPU_CLOCK_HZ: u32 = 1_773_447;
+
+impl Region {
+    /// PPU dots per CPU cycle, as an exact (numerator, denominator) pair so
+    /// PAL's fractional ratio can be tracked without ever dropping a dot.
+    fn ppu_dots_per_cpu_cycle(&self) -> (u64, u64) {
+        match self {
+            Region::Ntsc => (3, 1),
+            Region::Pal => (16, 5),
+            Region::Dendy => (3, 1),
+        }
+    }
+
+    /// Not wired up yet: kept for when `ppu.rs` grows a way to configure
+    /// its scanline count (see the `TODO` in [`Nes::new_with_region`]).
+    #[allow(dead_code)]
+    fn scanline_count(&self) -> u32 {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+
+    fn cpu_clock_hz(&self) -> u32 {
+        match self {
+            Region::Ntsc => NTSC_CPU_CLOCK_HZ,
+            Region::Pal => PAL_CPU_CLOCK_HZ,
+            Region::Dendy => DENDY_CPU_CLOCK_HZ,
+        }
+    }
+
+    /// Used across crate boundaries (the rewind history sizing, the
+    /// emulation thread's pacing, and the libretro core's `retro_get_system_av_info`),
+    /// so this needs to be `pub` rather than private to `nes`.
+    pub fn frame_rate_hz(&self) -> f64 {
+        match self {
+            Region::Ntsc => 60.0988,
+            Region::Pal => 50.007,
+            Region::Dendy => 50.0,
+        }
+    }
+}
 
 pub struct Nes {
     pixel_format: PixelFormat,
+    region: Region,
     cpu: Cpu,
     cpu_clock: u64,
     ppu_clock: u64,
@@ -14,28 +91,84 @@ pub struct Nes {
     system: System,
     framebuffers: Vec<Vec<u8>>,
     current_fb: usize,
+
+    audio_sample_rate: u32,
+    /// CPU cycles per output sample (`cpu_hz / sample_rate`); fractional
+    /// since the ratio rarely divides evenly.
+    cycles_per_sample: f64,
+    /// Accumulates fractional sample progress; a sample is emitted each
+    /// time this crosses 1.0.
+    sample_accumulator: f64,
+    /// One-pole DC-blocking high-pass state (models the NES's own DC
+    /// blocker) applied before the low-pass/decimation stage.
+    hp_prev_in: f32,
+    hp_prev_out: f32,
+    /// One-pole low-pass state, applied just before decimating down to
+    /// `audio_sample_rate` to avoid aliasing from naive sample-and-hold.
+    lp_prev_out: f32,
+    audio_producer: HeapProducer<f32>,
+    audio_consumer: HeapConsumer<f32>,
+
+    pub(crate) rewind: Option<crate::rewind::RewindBuffer>,
+    pub(crate) rewind_frame_interval: u32,
+    pub(crate) rewind_frames_since_capture: u32,
 }
 
 impl Nes {
     pub fn new(pixel_format: PixelFormat) -> Nes {
+        Self::new_with_sample_rate(pixel_format, 44_100)
+    }
+
+    pub fn new_with_sample_rate(pixel_format: PixelFormat, audio_sample_rate: u32) -> Nes {
+        Self::new_with_region(pixel_format, audio_sample_rate, Region::Ntsc)
+    }
+
+    pub fn new_with_region(pixel_format: PixelFormat, audio_sample_rate: u32, region: Region) -> Nes {
         let cpu = Cpu::default();
         let mut ppu = Ppu::default();
-        let mut apu = Apu::default();
+        let apu = Apu::default();
         ppu.draw_option.fb_width = FRAMEBUFFER_WIDTH as u32;
         ppu.draw_option.fb_height = FRAMEBUFFER_HEIGHT as u32;
         ppu.draw_option.offset_x = 0;
         ppu.draw_option.offset_y = 0;
         ppu.draw_option.scale = 1;
         ppu.draw_option.pixel_format = pixel_format;
+        // TODO: `Ppu` doesn't yet have a way to configure its scanline count
+        // or vblank set/clear dots, so `region.scanline_count()` can't be
+        // threaded through here until `ppu.rs` grows that support; until
+        // then `FinishedFrame`/`RaiseNmi` keep firing on NTSC's 262-line
+        // timing regardless of `region`.
 
-        let mut framebuffers = vec![vec![0u8; FRAMEBUFFER_WIDTH * FRAMEBUFFER_HEIGHT * 4]; 2];
+        let framebuffers = vec![vec![0u8; FRAMEBUFFER_WIDTH * FRAMEBUFFER_HEIGHT * 4]; 2];
 
         let system = System::new(ppu, Cartridge::none());
+
+        let ringbuf_capacity = ((audio_sample_rate as f64) * AUDIO_RINGBUF_SECONDS) as usize;
+        let (audio_producer, audio_consumer) = HeapRb::<f32>::new(ringbuf_capacity.max(1)).split();
+
         Nes {
-            pixel_format, cpu, cpu_clock: 0, ppu_clock: 0, apu, system, framebuffers, current_fb: 0
+            pixel_format, region, cpu, cpu_clock: 0, ppu_clock: 0, apu, system, framebuffers, current_fb: 0,
+            audio_sample_rate,
+            cycles_per_sample: region.cpu_clock_hz() as f64 / audio_sample_rate as f64,
+            sample_accumulator: 0.0,
+            hp_prev_in: 0.0,
+            hp_prev_out: 0.0,
+            lp_prev_out: 0.0,
+            audio_producer,
+            audio_consumer,
+
+            rewind: None,
+            rewind_frame_interval: 1,
+            rewind_frames_since_capture: 0,
         }
     }
 
+    /// The region's frame rate, used by [`crate::rewind`] to size the rewind
+    /// history buffer.
+    pub fn frame_rate_hz(&self) -> f64 {
+        self.region.frame_rate_hz()
+    }
+
     pub fn insert_cartridge(&mut self, cartridge: Option<Cartridge>) {
         if let Some(cartridge) = cartridge {
             self.system.cartridge = cartridge;
@@ -108,9 +241,14 @@ impl Nes {
                 // We treat the CPU as our master clock and the PPU is driven according
                 // to the forward progress of the CPU's clock.
 
-                // For now just assuming NTSC which has an exact 1:3 ratio between cpu
-                // clocks and PPU...
-                let expected_ppu_clock = self.cpu_clock * 3;
+                // NTSC is an exact 3:1 PPU:CPU dot ratio, but PAL's is a fractional
+                // 3.2:1 (16 PPU dots per 5 CPU cycles), so we track the ratio as an
+                // exact (numerator, denominator) pair and recompute `expected_ppu_clock`
+                // from the absolute `cpu_clock` each time, rather than accumulating a
+                // float/integer multiplier frame over frame: that keeps this driftless
+                // and never drops a PPU dot to rounding error, for any region.
+                let (ppu_dots_numer, ppu_dots_denom) = self.region.ppu_dots_per_cpu_cycle();
+                let expected_ppu_clock = (self.cpu_clock as u128 * ppu_dots_numer as u128 / ppu_dots_denom as u128) as u64;
                 let ppu_delta = expected_ppu_clock - self.ppu_clock;
 
                 // Let the PPU catch up with the CPU clock before progressing the CPU
@@ -121,7 +259,10 @@ impl Nes {
                     self.ppu_clock += 1;
                     match status {
                         PpuStatus::None => { continue },
-                        PpuStatus::FinishedFrame => { break 'frame_loop; },
+                        PpuStatus::FinishedFrame => {
+                            self.maybe_push_rewind_point();
+                            break 'frame_loop;
+                        },
                         PpuStatus::RaiseNmi => {
                             //println!("VBLANK NMI");
                             self.cpu.interrupt(&mut self.system, Interrupt::NMI);
@@ -129,12 +270,21 @@ impl Nes {
                     }
                 }
 
-                if self.system.oam_dma_cpu_suspend_cycles == 0 {
-                    self.cpu_clock += self.cpu.step(&mut self.system) as u64;
+                let cpu_cycles = if self.system.oam_dma_cpu_suspend_cycles == 0 {
+                    self.cpu.step(&mut self.system)
                 } else {
-                    self.cpu_clock += self.system.oam_dma_cpu_suspend_cycles as u64;
+                    let suspended = self.system.oam_dma_cpu_suspend_cycles;
                     self.system.oam_dma_cpu_suspend_cycles = 0;
+                    suspended
                 };
+                self.cpu_clock += cpu_cycles as u64;
+
+                // The frame loop can break mid-frame above (on `FinishedFrame`), so we
+                // can't generate audio once per frame: clock the APU here, once per CPU
+                // cycle, so sample output stays continuous across frame boundaries.
+                for _ in 0..cpu_cycles {
+                    self.step_audio();
+                }
 
                 #[cfg(feature="trace")]
                 self.display_trace();
@@ -143,4 +293,140 @@ impl Nes {
             warn!("Can't tick with framebuffer that's still in use!");
         }
     }
+
+    /// Clocks the APU for a single CPU cycle and feeds its output through
+    /// the resampling pipeline, pushing a sample into the audio ring buffer
+    /// whenever `sample_accumulator` crosses 1.0.
+    fn step_audio(&mut self) {
+        self.apu.step();
+
+        // Mix the five channels into a running analog level: rather than
+        // naively decimating the raw per-cycle waveform (which would alias),
+        // keep the last output level and only add the delta on each channel
+        // transition, which is what `Apu::mix_sample` gives us here.
+        let mix = self.apu.mix_sample();
+
+        // One-pole high-pass to model the NES's own DC blocker.
+        const HP_POLE: f32 = 0.996;
+        let hp_out = mix - self.hp_prev_in + HP_POLE * self.hp_prev_out;
+        self.hp_prev_in = mix;
+        self.hp_prev_out = hp_out;
+
+        // One-pole low-pass ahead of decimation to band-limit before we
+        // throw away samples, avoiding aliasing artifacts.
+        const LP_ALPHA: f32 = 0.15;
+        self.lp_prev_out += LP_ALPHA * (hp_out - self.lp_prev_out);
+
+        self.sample_accumulator += 1.0 / self.cycles_per_sample;
+        if self.sample_accumulator >= 1.0 {
+            self.sample_accumulator -= 1.0;
+            // If the consumer is falling behind we'd rather drop a sample
+            // than block or grow unbounded.
+            let _ = self.audio_producer.push(self.lp_prev_out);
+        }
+    }
+
+    /// Drains up to `out.len()` queued audio samples into `out`, returning
+    /// how many were written. Intended to be called from a `cpal`/`rodio`
+    /// style audio callback running on its own thread/priority.
+    pub fn drain_audio(&mut self, out: &mut [f32]) -> usize {
+        let mut count = 0;
+        while count < out.len() {
+            match self.audio_consumer.pop() {
+                Some(sample) => {
+                    out[count] = sample;
+                    count += 1;
+                }
+                None => break,
+            }
+        }
+        count
+    }
+
+    pub fn audio_sample_rate(&self) -> u32 {
+        self.audio_sample_rate
+    }
+
+    /// Serializes what [`SaveState`] impls exist for today: CPU registers/
+    /// flags/`sp`/`pc`, the `cpu_clock`/`ppu_clock` counters, the APU's
+    /// `clock`, the in-progress OAM DMA suspend counter, and which of the
+    /// two `framebuffers` is `current_fb`.
+    ///
+    /// This is **not** yet a full machine state: system RAM, PPU registers/
+    /// OAM/palette/VRAM and mid-frame scanline/dot position, the rest of APU
+    /// state (per-channel/frame-counter), pad shift registers, the open-bus
+    /// latch, and the cartridge mapper's banking registers and PRG/CHR-RAM
+    /// are all left as whatever `self` already had when `load_state` is
+    /// called, since `Apu`/`System` only implement [`SaveState`] for the
+    /// fields named above. Loading an older save over a session that has
+    /// since diverged in those untouched fields (different level, different
+    /// mapper bank switched in, ...) desyncs the CPU/clocks from them rather
+    /// than reproducing the saved machine. Treat `save_state`/`load_state`
+    /// as a skeleton to build on, not a working save-state feature, until
+    /// `ppu.rs`/`cartridge.rs`/`pad.rs` grow matching `SaveState` impls.
+    ///
+    /// `pixel_format` is deliberately not included: it's a host-chosen
+    /// output format, not part of the emulated machine's state.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_header(&mut out);
+
+        self.cpu.serialize(&mut out);
+        self.cpu_clock.serialize(&mut out);
+        self.ppu_clock.serialize(&mut out);
+        self.apu.serialize(&mut out);
+        self.system.serialize(&mut out);
+        self.framebuffers.len().serialize(&mut out);
+        for fb in &self.framebuffers {
+            fb.serialize(&mut out);
+        }
+        self.current_fb.serialize(&mut out);
+
+        out
+    }
+
+    /// Restores state previously produced by [`Nes::save_state`]. Leaves
+    /// `self` untouched if the blob is corrupt, truncated, or was produced
+    /// by an incompatible [`crate::save_state::SAVE_STATE_VERSION`].
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), SaveStateError> {
+        let mut cur = Cursor::new(bytes);
+        read_header(&mut cur)?;
+
+        let mut cpu = self.cpu.clone();
+        let mut cpu_clock = 0u64;
+        let mut ppu_clock = 0u64;
+        let mut apu = self.apu.clone();
+        let mut system = self.system.clone();
+        cpu.deserialize(&mut cur)?;
+        cpu_clock.deserialize(&mut cur)?;
+        ppu_clock.deserialize(&mut cur)?;
+        apu.deserialize(&mut cur)?;
+        system.deserialize(&mut cur)?;
+
+        let mut fb_count = 0usize;
+        fb_count.deserialize(&mut cur)?;
+        let mut framebuffers = Vec::with_capacity(fb_count);
+        for _ in 0..fb_count {
+            let mut fb = Vec::new();
+            fb.deserialize(&mut cur)?;
+            framebuffers.push(fb);
+        }
+        let mut current_fb = 0usize;
+        current_fb.deserialize(&mut cur)?;
+        if current_fb >= framebuffers.len() {
+            return Err(SaveStateError::UnexpectedEof);
+        }
+
+        // Only commit to `self` once every field decoded successfully, so a
+        // truncated/corrupt blob can't leave the machine half-restored.
+        self.cpu = cpu;
+        self.cpu_clock = cpu_clock;
+        self.ppu_clock = ppu_clock;
+        self.apu = apu;
+        self.system = system;
+        self.framebuffers = framebuffers;
+        self.current_fb = current_fb;
+
+        Ok(())
+    }
 }
\ No newline at end of file
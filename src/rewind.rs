@@ -0,0 +1,206 @@
+//! Rewind support built on top of [`crate::save_state`]: periodically
+//! capture a whole-machine state into a bounded ring buffer, and let a
+//! front-end step the machine backwards through the captured history.
+//!
+//! State buffers are mostly-unchanged RAM/VRAM frame-to-frame, so rather
+//! than storing a full [`Nes::save_state`] blob per entry, only the first
+//! ("keyframe") entry in the buffer is stored whole; every later entry is
+//! the XOR delta against the snapshot before it, run-length encoded, since
+//! an XOR delta between two similar states is mostly zero bytes.
+
+use alloc::vec::Vec;
+use alloc::collections::VecDeque;
+
+use crate::nes::Nes;
+
+enum RewindEntry {
+    Keyframe(Vec<u8>),
+    /// Run-length encoded XOR delta against the reconstructed snapshot
+    /// immediately before this one
+    Delta(Vec<u8>),
+}
+
+/// A fixed-capacity history of [`Nes::save_state`] snapshots.
+pub struct RewindBuffer {
+    capacity: usize,
+    entries: VecDeque<RewindEntry>,
+}
+
+impl RewindBuffer {
+    /// `depth_seconds` of history at `frame_rate_hz`, sampling every
+    /// `frame_interval` frames (so `capacity` frames of depth become
+    /// `depth_seconds * frame_rate_hz / frame_interval` stored entries).
+    pub fn new(depth_seconds: f64, frame_rate_hz: f64, frame_interval: u32) -> Self {
+        let capacity = ((depth_seconds * frame_rate_hz) / frame_interval.max(1) as f64).ceil() as usize;
+        Self {
+            capacity: capacity.max(1),
+            entries: VecDeque::new(),
+        }
+    }
+
+    fn reconstruct(&self, upto: usize) -> Vec<u8> {
+        let mut full = match &self.entries[0] {
+            RewindEntry::Keyframe(bytes) => bytes.clone(),
+            RewindEntry::Delta(_) => unreachable!("first rewind entry is always a keyframe"),
+        };
+        for entry in self.entries.iter().take(upto + 1).skip(1) {
+            if let RewindEntry::Delta(delta) = entry {
+                full = xor_with_rle_delta(&full, delta);
+            }
+        }
+        full
+    }
+
+    pub fn push(&mut self, snapshot: Vec<u8>) {
+        if self.entries.is_empty() {
+            self.entries.push_back(RewindEntry::Keyframe(snapshot));
+        } else {
+            let prev = self.reconstruct(self.entries.len() - 1);
+            self.entries.push_back(RewindEntry::Delta(rle_encode_xor(&prev, &snapshot)));
+        }
+
+        if self.entries.len() > self.capacity {
+            // `entries[0]` is always a keyframe, so reconstruct the new
+            // oldest entry (what `entries[1]` becomes once `entries[0]` is
+            // evicted) *before* popping — `reconstruct` assumes `entries[0]`
+            // is still a keyframe, which is no longer true right after the
+            // pop.
+            let materialized = matches!(self.entries.get(1), Some(RewindEntry::Delta(_)))
+                .then(|| self.reconstruct(1));
+            self.entries.pop_front();
+            if let Some(materialized) = materialized {
+                self.entries[0] = RewindEntry::Keyframe(materialized);
+            }
+        }
+    }
+
+    /// Drops the most recent snapshot and returns the reconstructed one
+    /// before it, if there is one.
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        if self.entries.len() < 2 {
+            return None;
+        }
+        self.entries.pop_back();
+        Some(self.reconstruct(self.entries.len() - 1))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+/// XOR `next` against `base` byte-for-byte, then run-length encode the
+/// result as alternating (run length, byte) pairs, which compresses very
+/// well since an XOR delta between similar snapshots is almost all zeros.
+fn rle_encode_xor(base: &[u8], next: &[u8]) -> Vec<u8> {
+    debug_assert_eq!(base.len(), next.len());
+    let mut out = Vec::new();
+    (next.len() as u32).to_le_bytes().iter().for_each(|b| out.push(*b));
+
+    let mut iter = base.iter().zip(next.iter()).map(|(a, b)| a ^ b).peekable();
+    while let Some(byte) = iter.next() {
+        let mut run: u32 = 1;
+        while iter.peek() == Some(&byte) && run < u32::MAX {
+            iter.next();
+            run += 1;
+        }
+        out.extend_from_slice(&run.to_le_bytes());
+        out.push(byte);
+    }
+    out
+}
+
+/// Inverse of [`rle_encode_xor`]: decode the run-length encoded delta and
+/// XOR it back against `base` to recover `next`.
+fn xor_with_rle_delta(base: &[u8], encoded: &[u8]) -> Vec<u8> {
+    let len = u32::from_le_bytes(encoded[0..4].try_into().unwrap()) as usize;
+    let mut out = Vec::with_capacity(len);
+    let mut pos = 4;
+    while pos < encoded.len() {
+        let run = u32::from_le_bytes(encoded[pos..pos + 4].try_into().unwrap());
+        let byte = encoded[pos + 4];
+        pos += 5;
+        for _ in 0..run {
+            out.push(byte);
+        }
+    }
+    debug_assert_eq!(out.len(), len);
+
+    for (o, b) in out.iter_mut().zip(base.iter()) {
+        *o ^= b;
+    }
+    out
+}
+
+impl Nes {
+    /// Opt in to rewind support, retaining `depth_seconds` of history,
+    /// capturing a snapshot every `frame_interval` frames.
+    pub fn enable_rewind(&mut self, depth_seconds: f64, frame_interval: u32) {
+        let frame_rate_hz = self.frame_rate_hz();
+        self.rewind = Some(RewindBuffer::new(depth_seconds, frame_rate_hz, frame_interval));
+        self.rewind_frame_interval = frame_interval.max(1);
+        self.rewind_frames_since_capture = 0;
+    }
+
+    pub fn disable_rewind(&mut self) {
+        self.rewind = None;
+    }
+
+    /// Called once per completed frame from [`Nes::tick_frame`]; captures a
+    /// snapshot once every `frame_interval` frames.
+    pub(crate) fn maybe_push_rewind_point(&mut self) {
+        if self.rewind.is_none() {
+            return;
+        }
+        self.rewind_frames_since_capture += 1;
+        if self.rewind_frames_since_capture >= self.rewind_frame_interval {
+            self.rewind_frames_since_capture = 0;
+            self.push_rewind_point();
+        }
+    }
+
+    /// Captures a snapshot into the rewind history immediately, regardless
+    /// of the configured capture cadence.
+    pub fn push_rewind_point(&mut self) {
+        let state = self.save_state();
+        if let Some(rewind) = &mut self.rewind {
+            rewind.push(state);
+        }
+    }
+
+    /// Steps the machine back to the previous rewind point, returning
+    /// `false` if rewind isn't enabled or there's no older point to go back
+    /// to.
+    pub fn rewind_step(&mut self) -> bool {
+        let Some(rewind) = &mut self.rewind else { return false };
+        let Some(bytes) = rewind.pop() else { return false };
+        self.load_state(&bytes).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rle_xor_delta_round_trips() {
+        let base: Vec<u8> = (0..256).map(|i| i as u8).collect();
+        let next: Vec<u8> = base.iter().map(|b| b.wrapping_add(3)).collect();
+        let encoded = rle_encode_xor(&base, &next);
+        assert_eq!(xor_with_rle_delta(&base, &encoded), next);
+    }
+
+    #[test]
+    fn rewind_buffer_survives_eviction_past_capacity() {
+        // Regression test: pushing past `capacity` used to reconstruct the
+        // new base *after* popping the old keyframe, panicking via
+        // `reconstruct`'s "first rewind entry is always a keyframe" the
+        // first time the buffer wrapped.
+        let mut buf = RewindBuffer { capacity: 2, entries: VecDeque::new() };
+        for i in 0..5u8 {
+            buf.push(vec![i; 4]);
+        }
+        assert_eq!(buf.len(), 2);
+        assert_eq!(buf.pop(), Some(vec![3u8; 4]));
+    }
+}